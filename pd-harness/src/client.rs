@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use pd::genesis;
+use penumbra_crypto::{
+    keys::SpendKey,
+    memo::{MemoPlaintext, MEMO_LEN_BYTES},
+    merkle::{NoteCommitmentTree, Tree, TreeExt},
+    note, Action, Address, Note, Nullifier, Transaction,
+};
+use penumbra_stake::{FundingStream, Validator};
+use rand_core::{CryptoRng, RngCore};
+
+/// A minimal wallet that tracks its notes by scanning the blocks committed by
+/// a [`Network`](crate::Network), so that tests can build valid transactions.
+pub struct Client {
+    spend_key: SpendKey,
+    chain_id: String,
+    /// A copy of the chain's note commitment tree, witnessing our own notes.
+    note_commitment_tree: NoteCommitmentTree,
+    /// Our unspent notes, indexed by the nullifier that will spend them.
+    notes: BTreeMap<Nullifier, Note>,
+}
+
+impl Client {
+    /// Create a client with a fresh spend key.
+    pub fn new<R: RngCore + CryptoRng>(rng: R, chain_id: &str) -> Self {
+        Self {
+            spend_key: SpendKey::generate(rng),
+            chain_id: chain_id.to_string(),
+            note_commitment_tree: NoteCommitmentTree::new(0),
+            notes: BTreeMap::new(),
+        }
+    }
+
+    /// The payment address with the given diversifier index.
+    pub fn address(&self, index: u64) -> Address {
+        self.spend_key
+            .incoming_viewing_key()
+            .payment_address(index.into())
+            .0
+    }
+
+    /// A genesis allocation of `amount` of `denom` to the address with the given index.
+    pub fn allocation(&self, index: u64, amount: u64, denom: &str) -> genesis::Allocation {
+        genesis::Allocation {
+            amount,
+            denom: denom.to_string(),
+            address: self.address(index),
+        }
+    }
+
+    /// A genesis validator with a fresh consensus key, funding this client.
+    pub fn validator<R: RngCore + CryptoRng>(&self, rng: R) -> Validator {
+        let validator_sk = tendermint::PrivateKey::Ed25519(ed25519_consensus::SigningKey::new(rng));
+        Validator::new(
+            validator_sk.public_key(),
+            100u32.into(),
+            vec![FundingStream {
+                address: self.address(0),
+                rate_bps: 100,
+            }],
+        )
+    }
+
+    /// Our unspent notes.
+    pub fn notes(&self) -> impl Iterator<Item = &Note> {
+        self.notes.values()
+    }
+
+    /// Pick up our notes from the genesis allocations.
+    pub fn scan_genesis(&mut self, app_state: &genesis::AppState) -> Result<(), anyhow::Error> {
+        // Rebuild the genesis transaction the same way pd does, so that the
+        // genesis notes can be scanned like any other outputs.
+        let mut tx_builder = Transaction::genesis_builder();
+        for allocation in &app_state.allocations {
+            tx_builder.add_output(allocation.note()?);
+        }
+        let genesis_tx = tx_builder.set_chain_id(self.chain_id.clone()).finalize()?;
+
+        self.scan_transactions(std::iter::once(genesis_tx));
+        Ok(())
+    }
+
+    /// Update the note commitment tree and our notes with the state changes of
+    /// the given committed transactions, in block order.
+    pub fn scan_transactions(&mut self, transactions: impl IntoIterator<Item = Transaction>) {
+        let ivk = self.spend_key.incoming_viewing_key();
+        let fvk = self.spend_key.full_viewing_key();
+
+        for transaction in transactions {
+            // pd inserts each transaction's note commitments into the tree in
+            // sorted order, so collect them before appending.
+            let mut new_notes = BTreeMap::<note::Commitment, Option<Note>>::new();
+            for action in transaction.transaction_body().actions {
+                match action {
+                    Action::Output(output) => {
+                        let note = Note::decrypt(
+                            &output.body.encrypted_note,
+                            ivk,
+                            &output.body.ephemeral_key,
+                        )
+                        .ok();
+                        new_notes.insert(output.body.note_commitment, note);
+                    }
+                    Action::Spend(spend) => {
+                        self.notes.remove(&spend.body.nullifier);
+                    }
+                }
+            }
+
+            for (note_commitment, note) in new_notes {
+                self.note_commitment_tree.append(&note_commitment);
+                if let Some(note) = note {
+                    self.note_commitment_tree.witness();
+                    let (position, _) = self
+                        .note_commitment_tree
+                        .authentication_path(&note_commitment)
+                        .expect("we just witnessed this commitment");
+                    self.notes
+                        .insert(fvk.derive_nullifier(position, &note_commitment), note);
+                }
+            }
+        }
+    }
+
+    /// Build a transaction spending `note` in full to `dest`, with no fee.
+    ///
+    /// This doesn't mark the note as spent; that happens once the transaction
+    /// is scanned in a committed block.
+    pub fn spend<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        note: &Note,
+        dest: &Address,
+    ) -> Result<Transaction, anyhow::Error> {
+        let note_commitment = note.commit();
+        let (position, auth_path) = self
+            .note_commitment_tree
+            .authentication_path(&note_commitment)
+            .ok_or_else(|| anyhow!("note is not in the note commitment tree"))?;
+        let merkle_path = (u64::from(position) as usize, auth_path);
+
+        Transaction::build_with_root(self.note_commitment_tree.root2())
+            .set_fee(0)
+            .set_chain_id(self.chain_id.clone())
+            .add_spend(
+                rng,
+                self.spend_key.clone(),
+                merkle_path,
+                note.clone(),
+                position,
+            )
+            .add_output(
+                rng,
+                dest,
+                note.value(),
+                MemoPlaintext([0u8; MEMO_LEN_BYTES]),
+                self.spend_key.outgoing_viewing_key(),
+            )
+            .finalize(rng)
+            .map_err(Into::into)
+    }
+}
@@ -0,0 +1,14 @@
+//! An in-process test network for the Penumbra node software.
+//!
+//! The harness runs several [`pd::App`] instances side by side, each backed by
+//! its own Postgres database, and stands in for Tendermint by driving them
+//! through the ABCI `InitChain`, `CheckTx`, `BeginBlock`, `DeliverTx`,
+//! `EndBlock` and `Commit` flow.  After every block it checks that all nodes
+//! computed the same results and the same app hash, so that nondeterminism
+//! between nodes shows up as a test failure rather than a chain halt.
+
+mod client;
+mod network;
+
+pub use client::Client;
+pub use network::{Block, Network};
@@ -0,0 +1,459 @@
+use anyhow::anyhow;
+use bytes::Bytes;
+use futures::future::try_join_all;
+use pd::{genesis, App, State};
+use penumbra_crypto::Transaction;
+use rand_core::{OsRng, RngCore};
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use tendermint::{
+    abci::{
+        request::{self, CheckTxKind},
+        types::LastCommitInfo,
+        Request, Response,
+    },
+    account, block, chain, AppHash, Hash, Time,
+};
+use tower::{Service, ServiceExt};
+use url::Url;
+
+/// The consensus parameters sent in `InitChain`, matching those of the testnets.
+const CONSENSUS_PARAMS: &str = r#"{
+  "block": {
+    "max_bytes": "22020096",
+    "max_gas": "-1"
+  },
+  "evidence": {
+    "max_age_num_blocks": "100000",
+    "max_age_duration": "172800000000000",
+    "max_bytes": "1048576"
+  },
+  "validator": {
+    "pub_key_types": [
+      "ed25519"
+    ]
+  },
+  "version": {
+    "app_version": "0"
+  }
+}"#;
+
+/// A set of `pd` instances driven through consensus in lockstep.
+///
+/// The network plays the role of Tendermint: transactions are submitted to
+/// the nodes' mempools with `CheckTx`, and each block is proposed by the nodes
+/// in round-robin order and executed by all of them.  Every node must agree on
+/// the `DeliverTx` results and the app hash of every block.
+pub struct Network {
+    nodes: Vec<Node>,
+    chain_id: chain::Id,
+    height: u32,
+    app_hash: Bytes,
+    database_uri: String,
+    /// Connection to the Postgres server, used to create and drop the node databases.
+    admin: Pool<Postgres>,
+    /// The databases created for the nodes, which are dropped when the
+    /// network is shut down or dropped.
+    databases: Vec<String>,
+}
+
+/// A block executed by every node in a [`Network`].
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub height: u32,
+    /// The transactions in the block, paired with their `DeliverTx` response codes.
+    pub transactions: Vec<(Bytes, u32)>,
+    pub app_hash: Bytes,
+}
+
+impl Block {
+    /// The `DeliverTx` response codes for the transactions in the block.
+    pub fn codes(&self) -> Vec<u32> {
+        self.transactions.iter().map(|(_, code)| *code).collect()
+    }
+
+    /// The transactions whose state changes were applied, in block order.
+    pub fn committed_transactions(&self) -> impl Iterator<Item = Transaction> + '_ {
+        self.transactions
+            .iter()
+            .filter(|(_, code)| *code == 0)
+            .map(|(tx, _)| {
+                Transaction::try_from(tx.as_ref()).expect("committed transactions are well-formed")
+            })
+    }
+}
+
+impl Network {
+    /// Start `num_nodes` nodes and run `InitChain` on each of them.
+    ///
+    /// `database_uri` must point to a Postgres server on which the user can
+    /// create databases.  Every node gets a fresh database, which is dropped
+    /// again by [`Network::shutdown`], or when the network is dropped.
+    pub async fn start(
+        database_uri: &str,
+        num_nodes: usize,
+        chain_id: &str,
+        app_state: genesis::AppState,
+    ) -> Result<Self, anyhow::Error> {
+        let chain_id: chain::Id = chain_id.parse()?;
+        let admin = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(database_uri)
+            .await?;
+
+        // From here on, returning an error drops `network`, which drops any
+        // databases created so far.
+        let mut network = Self {
+            nodes: Vec::with_capacity(num_nodes),
+            chain_id,
+            height: 0,
+            app_hash: Bytes::new(),
+            database_uri: database_uri.to_string(),
+            admin,
+            databases: Vec::with_capacity(num_nodes),
+        };
+
+        // Use a random prefix so that concurrently running tests don't collide.
+        let mut prefix = [0u8; 4];
+        OsRng.fill_bytes(&mut prefix);
+        let prefix = format!("pd_harness_{}", hex::encode(prefix));
+
+        for index in 0..num_nodes {
+            let database = format!("{}_{}", prefix, index);
+            tracing::info!(?database, "creating node database");
+            sqlx::query(&format!(r#"CREATE DATABASE "{}""#, database))
+                .execute(&network.admin)
+                .await?;
+            network.databases.push(database.clone());
+
+            let mut uri = Url::parse(database_uri)?;
+            uri.set_path(&database);
+            let state = State::connect(uri.as_str()).await?;
+
+            network.nodes.push(Node {
+                app: App::new(state).await?,
+                mempool: Vec::new(),
+            });
+        }
+
+        let init_chain = request::InitChain {
+            time: Time::now(),
+            chain_id: network.chain_id.to_string(),
+            consensus_params: serde_json::from_str(CONSENSUS_PARAMS)?,
+            // pd reads the initial validator set from the app state.
+            validators: Vec::new(),
+            app_state_bytes: serde_json::to_vec(&app_state)?.into(),
+            initial_height: 1u32.into(),
+        };
+
+        let app_hashes = try_join_all(
+            network
+                .nodes
+                .iter_mut()
+                .map(|node| node.init_chain(init_chain.clone())),
+        )
+        .await?;
+        network.app_hash = agree(0, app_hashes)?;
+
+        Ok(network)
+    }
+
+    /// The chain ID the network was started with.
+    pub fn chain_id(&self) -> &str {
+        self.chain_id.as_str()
+    }
+
+    /// The height of the last committed block.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The app hash agreed on by all nodes at the last committed block.
+    pub fn app_hash(&self) -> &[u8] {
+        &self.app_hash
+    }
+
+    /// Submit `tx` to every node's mempool, as if it had been gossiped, and
+    /// return each node's `CheckTx` response code.
+    pub async fn broadcast_tx(&mut self, tx: Transaction) -> Result<Vec<u32>, anyhow::Error> {
+        let tx = Bytes::from(Vec::<u8>::from(tx));
+        try_join_all(self.nodes.iter_mut().map(|node| node.submit(tx.clone()))).await
+    }
+
+    /// Submit `tx` to the mempool of a single node and return its `CheckTx`
+    /// response code.
+    pub async fn submit_tx(&mut self, node: usize, tx: Transaction) -> Result<u32, anyhow::Error> {
+        let node = self
+            .nodes
+            .get_mut(node)
+            .ok_or_else(|| anyhow!("no node with index {}", node))?;
+        node.submit(Vec::<u8>::from(tx).into()).await
+    }
+
+    /// Commit a block proposed by the next node in round-robin order,
+    /// containing all transactions in that node's mempool.
+    pub async fn commit_block(&mut self) -> Result<Block, anyhow::Error> {
+        let proposer = (self.height as usize + 1) % self.nodes.len();
+        let txs = self.nodes[proposer].mempool.clone();
+        tracing::debug!(proposer, num_txs = txs.len(), "proposing block");
+        self.execute(txs).await
+    }
+
+    /// Commit a block with exactly the given transactions, bypassing the
+    /// mempool checks, as a Byzantine proposer could.
+    pub async fn propose_block(&mut self, txs: Vec<Transaction>) -> Result<Block, anyhow::Error> {
+        let txs = txs
+            .into_iter()
+            .map(|tx| Bytes::from(Vec::<u8>::from(tx)))
+            .collect();
+        self.execute(txs).await
+    }
+
+    /// Stop all nodes and drop their databases.
+    pub async fn shutdown(mut self) -> Result<(), anyhow::Error> {
+        self.nodes.clear();
+        // On error, any databases that weren't dropped are retried on `Drop`.
+        drop_databases(&self.admin, &mut self.databases).await
+    }
+
+    async fn execute(&mut self, txs: Vec<Bytes>) -> Result<Block, anyhow::Error> {
+        let height = self.height + 1;
+        let begin = request::BeginBlock {
+            hash: Hash::None,
+            header: self.header(height)?,
+            last_commit_info: LastCommitInfo {
+                round: Default::default(),
+                votes: Vec::new(),
+            },
+            byzantine_validators: Vec::new(),
+        };
+
+        let executions = try_join_all(
+            self.nodes
+                .iter_mut()
+                .map(|node| node.execute_block(begin.clone(), height, &txs)),
+        )
+        .await?;
+        let Execution { codes, app_hash } = agree(height, executions)?;
+
+        try_join_all(self.nodes.iter_mut().map(|node| node.update_mempool(&txs))).await?;
+
+        tracing::info!(height, app_hash = ?hex::encode(&app_hash), "committed block");
+        self.height = height;
+        self.app_hash = app_hash.clone();
+
+        Ok(Block {
+            height,
+            transactions: txs.into_iter().zip(codes).collect(),
+            app_hash,
+        })
+    }
+
+    fn header(&self, height: u32) -> Result<block::Header, anyhow::Error> {
+        Ok(block::Header {
+            version: block::header::Version { block: 11, app: 1 },
+            chain_id: self.chain_id.clone(),
+            height: height.into(),
+            time: Time::now(),
+            last_block_id: None,
+            last_commit_hash: None,
+            data_hash: None,
+            validators_hash: Hash::None,
+            next_validators_hash: Hash::None,
+            consensus_hash: Hash::None,
+            app_hash: AppHash::try_from(self.app_hash.to_vec())?,
+            last_results_hash: None,
+            evidence_hash: None,
+            proposer_address: account::Id::new([0; 20]),
+        })
+    }
+}
+
+impl Drop for Network {
+    /// Drop the node databases if [`Network::shutdown`] wasn't reached, e.g.
+    /// because `start` failed or a test panicked.
+    fn drop(&mut self) {
+        if self.databases.is_empty() {
+            return;
+        }
+        self.nodes.clear();
+        let database_uri = self.database_uri.clone();
+        let mut databases = std::mem::take(&mut self.databases);
+
+        // Drop can't be async, and the pool we already have is tied to the
+        // caller's runtime, so connect again from a runtime on another thread.
+        let result = std::thread::spawn(move || -> Result<(), anyhow::Error> {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(async {
+                    let admin = PgPoolOptions::new()
+                        .max_connections(1)
+                        .connect(&database_uri)
+                        .await?;
+                    drop_databases(&admin, &mut databases).await
+                })
+        })
+        .join();
+
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!(?e, "could not drop node databases"),
+            Err(_) => tracing::error!("panicked while dropping node databases"),
+        }
+    }
+}
+
+/// A single `pd` instance participating in a [`Network`].
+struct Node {
+    app: App,
+    /// Transactions accepted by this node's `CheckTx`, in arrival order.
+    mempool: Vec<Bytes>,
+}
+
+/// The outcome of executing a block on a single node.
+#[derive(Debug, PartialEq, Eq)]
+struct Execution {
+    codes: Vec<u32>,
+    app_hash: Bytes,
+}
+
+impl Node {
+    async fn call(&mut self, request: Request) -> Result<Response, anyhow::Error> {
+        self.app
+            .ready()
+            .await
+            .map_err(|e| anyhow!(e))?
+            .call(request)
+            .await
+            .map_err(|e| anyhow!(e))
+    }
+
+    async fn init_chain(&mut self, init_chain: request::InitChain) -> Result<Bytes, anyhow::Error> {
+        match self.call(Request::InitChain(init_chain)).await? {
+            Response::InitChain(rsp) => Ok(rsp.app_hash),
+            rsp => Err(anyhow!("unexpected response to InitChain: {:?}", rsp)),
+        }
+    }
+
+    async fn check_tx(&mut self, tx: Bytes, kind: CheckTxKind) -> Result<u32, anyhow::Error> {
+        match self
+            .call(Request::CheckTx(request::CheckTx { tx, kind }))
+            .await?
+        {
+            Response::CheckTx(rsp) => Ok(rsp.code),
+            rsp => Err(anyhow!("unexpected response to CheckTx: {:?}", rsp)),
+        }
+    }
+
+    /// Run a new transaction through `CheckTx`, adding it to the mempool if it passes.
+    async fn submit(&mut self, tx: Bytes) -> Result<u32, anyhow::Error> {
+        let code = self.check_tx(tx.clone(), CheckTxKind::New).await?;
+        if code == 0 {
+            self.mempool.push(tx);
+        }
+        Ok(code)
+    }
+
+    /// Remove the transactions included in a block from the mempool and
+    /// recheck the remaining ones, as Tendermint does after every commit.
+    async fn update_mempool(&mut self, included: &[Bytes]) -> Result<(), anyhow::Error> {
+        let pending = std::mem::take(&mut self.mempool);
+        for tx in pending.into_iter().filter(|tx| !included.contains(tx)) {
+            if self.check_tx(tx.clone(), CheckTxKind::Recheck).await? == 0 {
+                self.mempool.push(tx);
+            }
+        }
+        Ok(())
+    }
+
+    async fn execute_block(
+        &mut self,
+        begin: request::BeginBlock,
+        height: u32,
+        txs: &[Bytes],
+    ) -> Result<Execution, anyhow::Error> {
+        match self.call(Request::BeginBlock(begin)).await? {
+            Response::BeginBlock(_) => {}
+            rsp => return Err(anyhow!("unexpected response to BeginBlock: {:?}", rsp)),
+        }
+
+        // Send the DeliverTx requests without waiting for the previous
+        // responses, like tower-abci does.  Each response future has to make
+        // progress while we wait for the app to become ready again, since pd
+        // processes DeliverTx sequentially and only becomes ready once the
+        // previous request has completed, so spawn them rather than holding
+        // them unpolled.
+        let mut deliveries = Vec::with_capacity(txs.len());
+        for tx in txs {
+            self.app.ready().await.map_err(|e| anyhow!(e))?;
+            deliveries
+                .push(tokio::spawn(self.app.call(Request::DeliverTx(
+                    request::DeliverTx { tx: tx.clone() },
+                ))));
+        }
+        let mut codes = Vec::with_capacity(deliveries.len());
+        for delivery in deliveries {
+            match delivery.await?.map_err(|e| anyhow!(e))? {
+                Response::DeliverTx(rsp) => codes.push(rsp.code),
+                rsp => return Err(anyhow!("unexpected response to DeliverTx: {:?}", rsp)),
+            }
+        }
+
+        match self
+            .call(Request::EndBlock(request::EndBlock {
+                height: height.into(),
+            }))
+            .await?
+        {
+            Response::EndBlock(_) => {}
+            rsp => return Err(anyhow!("unexpected response to EndBlock: {:?}", rsp)),
+        }
+
+        let app_hash = match self.call(Request::Commit).await? {
+            Response::Commit(rsp) => rsp.data,
+            rsp => return Err(anyhow!("unexpected response to Commit: {:?}", rsp)),
+        };
+
+        Ok(Execution { codes, app_hash })
+    }
+}
+
+/// Drop the given databases, removing each one from `databases` once it has
+/// been dropped, so that the remaining ones can be retried on error.
+async fn drop_databases(
+    admin: &Pool<Postgres>,
+    databases: &mut Vec<String>,
+) -> Result<(), anyhow::Error> {
+    while let Some(database) = databases.last() {
+        tracing::info!(?database, "dropping node database");
+        // The node's connection pool may not have closed yet, so force it.
+        sqlx::query(&format!(r#"DROP DATABASE "{}" WITH (FORCE)"#, database))
+            .execute(admin)
+            .await?;
+        databases.pop();
+    }
+    Ok(())
+}
+
+/// Check that every node produced the same outcome at `height`, and return it.
+fn agree<T: PartialEq + std::fmt::Debug>(
+    height: u32,
+    outcomes: Vec<T>,
+) -> Result<T, anyhow::Error> {
+    let mut outcomes = outcomes.into_iter().enumerate();
+    let (_, expected) = outcomes
+        .next()
+        .ok_or_else(|| anyhow!("network has no nodes"))?;
+    for (index, outcome) in outcomes {
+        if outcome != expected {
+            return Err(anyhow!(
+                "node {} diverged from node 0 at height {}: {:?} != {:?}",
+                index,
+                height,
+                outcome,
+                expected
+            ));
+        }
+    }
+    Ok(expected)
+}
@@ -0,0 +1,185 @@
+use std::{future::Future, time::Duration};
+
+use bytes::Bytes;
+use pd::genesis;
+use pd_harness::{Client, Network};
+use rand_core::OsRng;
+
+const CHAIN_ID: &str = "penumbra-harness";
+
+/// How long a single test may run before it's considered hung.
+const TEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Run a test body, failing instead of hanging if it doesn't finish in time.
+async fn with_timeout(test: impl Future<Output = ()>) {
+    tokio::time::timeout(TEST_TIMEOUT, test)
+        .await
+        .expect("test timed out")
+}
+
+/// Start a network of `num_nodes` nodes, or return `None` if no Postgres
+/// server was configured, so that the tests are skipped locally.
+///
+/// In CI the server must be configured, so that a broken setup can't silently
+/// skip the tests.
+async fn start(num_nodes: usize, app_state: genesis::AppState) -> Option<Network> {
+    let database_uri = match std::env::var("PD_HARNESS_DATABASE_URL") {
+        Ok(uri) => uri,
+        Err(_) if std::env::var_os("CI").is_some() => {
+            panic!("PD_HARNESS_DATABASE_URL must be set in CI")
+        }
+        Err(_) => {
+            eprintln!("PD_HARNESS_DATABASE_URL is not set, skipping test");
+            return None;
+        }
+    };
+    Some(
+        Network::start(&database_uri, num_nodes, CHAIN_ID, app_state)
+            .await
+            .expect("can start network"),
+    )
+}
+
+/// A client holding `num_notes` genesis notes.
+fn funded_client(num_notes: u64) -> (Client, genesis::AppState) {
+    let mut client = Client::new(OsRng, CHAIN_ID);
+    let app_state = genesis::AppState {
+        allocations: (0..num_notes)
+            .map(|i| client.allocation(i, 1_000 + i, "upenumbra"))
+            .collect(),
+        epoch_duration: 300,
+        validators: vec![client.validator(OsRng)],
+    };
+    client
+        .scan_genesis(&app_state)
+        .expect("can scan genesis allocations");
+    (client, app_state)
+}
+
+#[tokio::test]
+async fn nodes_agree_on_transfers() {
+    with_timeout(async {
+        let (mut client, app_state) = funded_client(4);
+        let mut network = match start(4, app_state).await {
+            Some(network) => network,
+            None => return,
+        };
+
+        for i in 0..4 {
+            let note = client.notes().next().expect("client has notes").clone();
+            let tx = client
+                .spend(&mut OsRng, &note, &client.address(10 + i))
+                .unwrap();
+            assert_eq!(network.broadcast_tx(tx).await.unwrap(), vec![0; 4]);
+
+            let block = network.commit_block().await.unwrap();
+            assert_eq!(block.codes(), vec![0]);
+            client.scan_transactions(block.committed_transactions());
+        }
+
+        // The transferred notes were spent and re-received.
+        assert_eq!(client.notes().count(), 4);
+
+        network.shutdown().await.unwrap();
+    })
+    .await
+}
+
+#[tokio::test]
+async fn mempool_rejects_double_spends() {
+    with_timeout(async {
+        let (mut client, app_state) = funded_client(1);
+        let mut network = match start(3, app_state).await {
+            Some(network) => network,
+            None => return,
+        };
+
+        let note = client.notes().next().expect("client has notes").clone();
+        let tx1 = client.spend(&mut OsRng, &note, &client.address(1)).unwrap();
+        let tx2 = client.spend(&mut OsRng, &note, &client.address(2)).unwrap();
+
+        assert_eq!(network.broadcast_tx(tx1).await.unwrap(), vec![0, 0, 0]);
+        assert_eq!(network.broadcast_tx(tx2).await.unwrap(), vec![1, 1, 1]);
+
+        let block = network.commit_block().await.unwrap();
+        assert_eq!(block.codes(), vec![0]);
+        client.scan_transactions(block.committed_transactions());
+        assert_eq!(client.notes().count(), 1);
+
+        // The mempools were cleared by the commit, so the next block is empty.
+        let block = network.commit_block().await.unwrap();
+        assert!(block.transactions.is_empty());
+
+        network.shutdown().await.unwrap();
+    })
+    .await
+}
+
+#[tokio::test]
+async fn conflicting_spends_in_one_block() {
+    with_timeout(async {
+        let (mut client, app_state) = funded_client(1);
+        let mut network = match start(4, app_state).await {
+            Some(network) => network,
+            None => return,
+        };
+
+        // A Byzantine proposer can include both spends of the same note; every
+        // node must apply the first one and reject the second.
+        let note = client.notes().next().expect("client has notes").clone();
+        let tx1 = client.spend(&mut OsRng, &note, &client.address(1)).unwrap();
+        let tx2 = client.spend(&mut OsRng, &note, &client.address(2)).unwrap();
+
+        let block = network.propose_block(vec![tx1, tx2]).await.unwrap();
+        assert_eq!(block.codes(), vec![0, 1]);
+        client.scan_transactions(block.committed_transactions());
+        assert_eq!(client.notes().count(), 1);
+
+        network.shutdown().await.unwrap();
+    })
+    .await
+}
+
+#[tokio::test]
+async fn nodes_agree_with_transactions_on_different_proposers() {
+    with_timeout(async {
+        let (mut client, app_state) = funded_client(4);
+        let mut network = match start(2, app_state).await {
+            Some(network) => network,
+            None => return,
+        };
+
+        // Submit each transaction to a single node, so that the blocks depend on
+        // which node proposes them.
+        let notes = client.notes().cloned().collect::<Vec<_>>();
+        let mut submitted = Vec::new();
+        for (i, note) in notes.iter().enumerate() {
+            let tx = client.spend(&mut OsRng, note, &client.address(0)).unwrap();
+            submitted.push(Bytes::from(Vec::<u8>::from(tx.clone())));
+            assert_eq!(network.submit_tx(i % 2, tx).await.unwrap(), 0);
+        }
+
+        // Block 1 is proposed by node 1, and contains only the transactions
+        // submitted to it.
+        let block = network.commit_block().await.unwrap();
+        assert_eq!(
+            block.transactions,
+            vec![(submitted[1].clone(), 0), (submitted[3].clone(), 0)]
+        );
+        client.scan_transactions(block.committed_transactions());
+
+        // Block 2 is proposed by node 0, whose transactions were rechecked and
+        // kept in its mempool after block 1.
+        let block = network.commit_block().await.unwrap();
+        assert_eq!(
+            block.transactions,
+            vec![(submitted[0].clone(), 0), (submitted[2].clone(), 0)]
+        );
+        client.scan_transactions(block.committed_transactions());
+
+        assert_eq!(client.notes().count(), notes.len());
+
+        network.shutdown().await.unwrap();
+    })
+    .await
+}